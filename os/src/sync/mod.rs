@@ -0,0 +1,100 @@
+//! Synchronization primitives used across the kernel.
+//!
+//! [`UPSafeCell`] is for state that is only ever touched by a single hart
+//! at a time (e.g. a `Processor`'s own bookkeeping); it is cheap but gives
+//! no cross-core guarantee. [`SpinSafeCell`] is for state genuinely shared
+//! across harts, such as the global ready queue.
+
+use core::cell::{RefCell, RefMut};
+use core::cell::UnsafeCell;
+use core::ops::{Deref, DerefMut};
+use core::sync::atomic::{AtomicBool, Ordering};
+
+/// Wraps a `RefCell` with the promise that it is only ever accessed from a
+/// single hart at a time, with interrupts off for the duration of the
+/// borrow. Not safe to share across cores.
+pub struct UPSafeCell<T> {
+    inner: RefCell<T>,
+}
+
+unsafe impl<T> Sync for UPSafeCell<T> {}
+
+impl<T> UPSafeCell<T> {
+    /// Wrap `value`.
+    ///
+    /// # Safety
+    /// The caller must guarantee this cell is only ever accessed from one
+    /// hart at a time.
+    pub unsafe fn new(value: T) -> Self {
+        Self {
+            inner: RefCell::new(value),
+        }
+    }
+
+    /// Mutably borrow the inner value
+    pub fn exclusive_access(&self) -> RefMut<'_, T> {
+        self.inner.borrow_mut()
+    }
+
+    /// Immutably borrow the inner value
+    pub fn readonly_access(&self) -> core::cell::Ref<'_, T> {
+        self.inner.borrow()
+    }
+}
+
+/// A spinlock-backed cell for state shared across harts, such as
+/// `TASK_MANAGER`'s ready queue. Busy-waits until the lock is free, then
+/// hands out exclusive access guarded by a RAII guard.
+pub struct SpinSafeCell<T> {
+    locked: AtomicBool,
+    inner: UnsafeCell<T>,
+}
+
+unsafe impl<T> Sync for SpinSafeCell<T> {}
+
+impl<T> SpinSafeCell<T> {
+    /// Wrap `value`
+    pub fn new(value: T) -> Self {
+        Self {
+            locked: AtomicBool::new(false),
+            inner: UnsafeCell::new(value),
+        }
+    }
+
+    /// Spin until the lock is free, then return an exclusive guard
+    pub fn exclusive_access(&self) -> SpinSafeCellGuard<'_, T> {
+        while self
+            .locked
+            .compare_exchange_weak(false, true, Ordering::Acquire, Ordering::Relaxed)
+            .is_err()
+        {
+            core::hint::spin_loop();
+        }
+        SpinSafeCellGuard { cell: self }
+    }
+}
+
+/// RAII guard returned by [`SpinSafeCell::exclusive_access`]; releases the
+/// lock on drop.
+pub struct SpinSafeCellGuard<'a, T> {
+    cell: &'a SpinSafeCell<T>,
+}
+
+impl<'a, T> Drop for SpinSafeCellGuard<'a, T> {
+    fn drop(&mut self) {
+        self.cell.locked.store(false, Ordering::Release);
+    }
+}
+
+impl<'a, T> Deref for SpinSafeCellGuard<'a, T> {
+    type Target = T;
+    fn deref(&self) -> &T {
+        unsafe { &*self.cell.inner.get() }
+    }
+}
+
+impl<'a, T> DerefMut for SpinSafeCellGuard<'a, T> {
+    fn deref_mut(&mut self) -> &mut T {
+        unsafe { &mut *self.cell.inner.get() }
+    }
+}
@@ -1,50 +1,110 @@
 //!Implementation of [`TaskManager`]
 use super::TaskControlBlock;
-use crate::sync::UPSafeCell;
-use alloc::collections::{LinkedList};
+use crate::sync::SpinSafeCell;
+use alloc::collections::BinaryHeap;
 use alloc::sync::Arc;
-use core::usize;
+use core::cmp::Ordering;
 use lazy_static::*;
+
+/// The fixed "lap" distance used to derive a task's `pass` from its
+/// `priority` (`pass = BIG_STRIDE / priority`). Must be large enough that
+/// the gap between the largest and smallest live `stride` never exceeds it,
+/// which is what makes the wrapping comparison below sound.
+pub const BIG_STRIDE: usize = 0xFFFF;
+
+/// Compare two wrapping `stride` counters, returning `true` if `a` should be
+/// treated as strictly smaller than `b`.
+///
+/// `stride` is a `usize` that keeps incrementing by a task's `pass` every
+/// time it runs, so it eventually wraps past `usize::MAX`, and a plain `<`
+/// would then rank a just-wrapped stride below all the others. Given the
+/// scheduling invariant that the largest and smallest live strides never
+/// differ by more than [`BIG_STRIDE`], `a.wrapping_sub(b)` is at most
+/// `BIG_STRIDE` when `a` is level with or ahead of `b` (even across the
+/// wrap) and far above it only when `a` is genuinely behind `b`.
+fn stride_less(a: usize, b: usize) -> bool {
+    a.wrapping_sub(b) > BIG_STRIDE
+}
+
+/// Wraps a ready `TaskControlBlock` so it can sit in a [`BinaryHeap`]
+/// ordered by stride, smallest stride first.
+///
+/// Invariant: a task's `stride` must not change while its `StrideEntry` is
+/// in the heap. `stride` only ever advances when a task is dispatched in
+/// `run_tasks`, i.e. after it has already been popped out of the
+/// heap via `fetch`, so a plain (non-re-heapifying) `BinaryHeap` is sound.
+struct StrideEntry(Arc<TaskControlBlock>);
+
+impl StrideEntry {
+    fn stride(&self) -> usize {
+        self.0.inner_exclusive_access().stride
+    }
+}
+
+impl PartialEq for StrideEntry {
+    fn eq(&self, other: &Self) -> bool {
+        self.stride() == other.stride()
+    }
+}
+
+impl Eq for StrideEntry {}
+
+impl PartialOrd for StrideEntry {
+    fn partial_cmp(&self, other: &Self) -> Option<Ordering> {
+        Some(self.cmp(other))
+    }
+}
+
+impl Ord for StrideEntry {
+    /// `BinaryHeap` is a max-heap, but `fetch` wants the *smallest* stride,
+    /// so a smaller stride must compare as `Greater`.
+    fn cmp(&self, other: &Self) -> Ordering {
+        let (a, b) = (self.stride(), other.stride());
+        if a == b {
+            Ordering::Equal
+        } else if stride_less(a, b) {
+            Ordering::Greater
+        } else {
+            Ordering::Less
+        }
+    }
+}
+
 ///A array of `TaskControlBlock` that is thread-safe
 pub struct TaskManager {
-    ready_list: LinkedList<Arc<TaskControlBlock>>,
+    ready_queue: BinaryHeap<StrideEntry>,
 }
 
-/// A simple FIFO scheduler.
+/// A stride scheduler: `fetch` always returns the ready task whose `stride`
+/// is smallest under the wrapping-safe comparison of `stride_less`, in
+/// O(log n) instead of scanning the whole ready set.
 impl TaskManager {
     ///Creat an empty TaskManager
     pub fn new() -> Self {
         Self {
-            ready_list: LinkedList::new(),
+            ready_queue: BinaryHeap::new(),
         }
     }
     /// Add process back to ready queue
     pub fn add(&mut self, task: Arc<TaskControlBlock>) {
-        self.ready_list.push_back(task);
+        self.ready_queue.push(StrideEntry(task));
     }
-    /// Take a process out of the ready queue
+    /// Take the ready task with the smallest `stride` out of the ready queue
     pub fn fetch(&mut self) -> Option<Arc<TaskControlBlock>> {
-        let mut i = 0;
-        let mut min_stride = usize::MAX;
-        let mut min_i:isize = -1;
-        for task in &self.ready_list {
-            if task.inner_exclusive_access().stride < min_stride {
-                min_stride = task.inner_exclusive_access().stride;
-                min_i = i;
-            }
-            i = i+1;
-        }
-        if min_i == -1 {
-            return None;
-        }
-        Some(self.ready_list.remove(min_i as usize))
+        self.ready_queue.pop().map(|entry| entry.0)
     }
 }
 
 lazy_static! {
     /// TASK_MANAGER instance through lazy_static!
-    pub static ref TASK_MANAGER: UPSafeCell<TaskManager> =
-        unsafe { UPSafeCell::new(TaskManager::new()) };
+    ///
+    /// Every hart's `run_tasks` loop fetches from this same ready queue, so
+    /// it is backed by [`SpinSafeCell`] rather than [`crate::sync::UPSafeCell`]:
+    /// the latter is only sound for state a single hart ever touches, and
+    /// two harts racing on `exclusive_access` here would be a genuine data
+    /// race, not just a borrow-checker technicality.
+    pub static ref TASK_MANAGER: SpinSafeCell<TaskManager> =
+        SpinSafeCell::new(TaskManager::new());
 }
 
 /// Add process to ready queue
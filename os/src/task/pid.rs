@@ -0,0 +1,61 @@
+//! Pid allocation.
+//!
+//! Pids are handed out sequentially and returned to the pool once `waitpid`
+//! reaps the owning task, so a long-running system doesn't leak a pid on
+//! every fork/exit cycle. `fork`/`waitpid` on any hart can allocate or
+//! recycle a pid, so the pool is guarded by a [`SpinSafeCell`] rather than a
+//! `UPSafeCell`, same reasoning as `TASK_MANAGER`.
+
+use crate::sync::SpinSafeCell;
+use alloc::vec::Vec;
+use lazy_static::*;
+
+struct PidAllocator {
+    next: usize,
+    recycled: Vec<usize>,
+}
+
+impl PidAllocator {
+    fn new() -> Self {
+        Self {
+            next: 0,
+            recycled: Vec::new(),
+        }
+    }
+
+    fn alloc(&mut self) -> usize {
+        if let Some(pid) = self.recycled.pop() {
+            pid
+        } else {
+            self.next += 1;
+            self.next - 1
+        }
+    }
+
+    fn dealloc(&mut self, pid: usize) {
+        assert!(pid < self.next, "pid {} was never allocated", pid);
+        assert!(
+            !self.recycled.contains(&pid),
+            "pid {} freed twice",
+            pid
+        );
+        self.recycled.push(pid);
+    }
+}
+
+lazy_static! {
+    static ref PID_ALLOCATOR: SpinSafeCell<PidAllocator> =
+        SpinSafeCell::new(PidAllocator::new());
+}
+
+/// Allocate a fresh pid, reusing one returned by [`dealloc_pid`] if any are
+/// available.
+pub fn pid_alloc() -> usize {
+    PID_ALLOCATOR.exclusive_access().alloc()
+}
+
+/// Return `pid` to the pool. Called once its owning task has been reaped by
+/// `waitpid`, so a later `fork` can hand it out again.
+pub fn dealloc_pid(pid: usize) {
+    PID_ALLOCATOR.exclusive_access().dealloc(pid);
+}
@@ -3,18 +3,58 @@
 //! Here, the continuous operation of user apps in CPU is maintained,
 //! the current running state of CPU is recorded,
 //! and the replacement and transfer of control flow of different applications are executed.
+//!
+//! Each hart gets its own [`Processor`] (see [`PROCESSORS`]/[`hart_id`]), so
+//! "current task" and idle control flow are per-core; the ready queue they
+//! pull from remains a single structure shared by all harts.
 
 use super::__switch;
-use super::{fetch_task, TaskStatus};
+use super::{add_task, fetch_task, TaskStatus};
 use super::{TaskContext, TaskControlBlock};
+use super::pid::dealloc_pid;
 use crate::sync::UPSafeCell;
 use crate::trap::TrapContext;
 use alloc::sync::Arc;
 use lazy_static::*;
+use crate::config::MAX_SYSCALL_NUM;
 use crate::mm::{MapPermission, VirtAddr};
+use crate::task::manager::BIG_STRIDE;
 use crate::task::task::TaskInfo;
 use crate::timer;
 
+/// Per-syscall accounting: how many times a syscall id has been invoked,
+/// how many timer cycles were spent handling it in total, and when the
+/// first and most recent call happened.
+///
+/// This generalizes the flat `syscall_times` counter `TaskInfo` already
+/// exposes; it does not replace it, so existing `TaskInfo` consumers are
+/// unaffected.
+///
+/// The task-side half of this lives here: [`record_syscall_entry`]/
+/// [`record_syscall_exit`] do the bookkeeping, [`task_stat`] snapshots it.
+/// Nothing in this module calls them: calling those two around every
+/// syscall, and copying a `TaskStat` out to a user buffer as
+/// `sys_task_stat`, is the trap/syscall dispatcher's job.
+#[derive(Clone, Copy, Default)]
+pub struct SyscallStat {
+    /// Number of times this syscall has been invoked
+    pub count: u32,
+    /// Total timer cycles spent inside the kernel handling this syscall
+    pub cycles: usize,
+    /// Timer reading at the first call, or `0` if never called
+    pub first_call: usize,
+    /// Timer reading at the most recent call
+    pub last_call: usize,
+}
+
+/// A snapshot of a task's full syscall accounting, copied out to user space
+/// by `sys_task_stat`.
+#[derive(Clone)]
+pub struct TaskStat {
+    /// Per-syscall-id accounting, see [`SyscallStat`]
+    pub syscalls: [SyscallStat; MAX_SYSCALL_NUM],
+}
+
 /// Processor management structure
 pub struct Processor {
     ///The task currently executing on the current processor
@@ -54,6 +94,39 @@ impl Processor {
         task.inner_exclusive_access().syscall_times[syscall_id] += 1;
     }
 
+    /// Record that `syscall_id` was just entered, bumping its call count and
+    /// first/last-call timestamps. Returns the entry timer reading so the
+    /// caller can hand it back to [`Processor::record_syscall_exit`] once
+    /// the syscall has been handled.
+    pub fn record_syscall_entry(&self, syscall_id: usize) -> usize {
+        let task = self.current().unwrap();
+        let mut inner = task.inner_exclusive_access();
+        let now = timer::get_time();
+        let stat = &mut inner.syscall_stats[syscall_id];
+        if stat.count == 0 {
+            stat.first_call = now;
+        }
+        stat.count += 1;
+        stat.last_call = now;
+        now
+    }
+
+    /// Fold the cycles elapsed since `entry_time` into the running total for
+    /// `syscall_id`.
+    pub fn record_syscall_exit(&self, syscall_id: usize, entry_time: usize) {
+        let task = self.current().unwrap();
+        let mut inner = task.inner_exclusive_access();
+        inner.syscall_stats[syscall_id].cycles += timer::get_time() - entry_time;
+    }
+
+    /// Snapshot the current task's full syscall accounting
+    pub fn task_stat(&self) -> TaskStat {
+        let task = self.current().unwrap();
+        TaskStat {
+            syscalls: task.inner_exclusive_access().syscall_stats,
+        }
+    }
+
     /// get current `Running` task info
     pub fn get_current_task(&self) -> TaskInfo {
         let task = self.current().unwrap();
@@ -67,6 +140,12 @@ impl Processor {
     }
 
     ///map memory of current task
+    ///
+    /// Lazy: this only records `[start, start+len)` and its permissions in
+    /// the task's list of lazy areas; `memory_set` is not touched. No
+    /// physical frame is allocated here; each page
+    /// is allocated on first touch, when the resulting page fault reaches
+    /// [`handle_lazy_page_fault`].
     pub fn mmap(&self, _start: usize, _len: usize, _port: usize) -> isize {
         let start = VirtAddr::from(_start);
         if start.page_offset() != 0 {
@@ -89,7 +168,7 @@ impl Processor {
 
 
         //print!("_port:{}", _port as u8);
-        if !task.insert_framed_area(start, end, map_perm) {
+        if !task.insert_lazy_framed_area(start, end, map_perm) {
             return -1;
         }
 
@@ -112,21 +191,99 @@ impl Processor {
 
         0
     }
+
+    /// Set the priority of the current task, used by `sys_set_priority`.
+    ///
+    /// Rejects `prio < 2`, returning `-1`; a priority of 0 would otherwise
+    /// divide by zero in `pass = BIG_STRIDE / priority`.
+    pub fn set_priority(&self, prio: isize) -> isize {
+        if prio < 2 {
+            return -1;
+        }
+        let task = self.current().unwrap();
+        let mut inner = task.inner_exclusive_access();
+        inner.priority = prio as usize;
+        inner.pass = BIG_STRIDE / inner.priority;
+        prio
+    }
+}
+
+/// Upper bound on the number of harts this kernel can boot. Sized generously
+/// since `PROCESSORS` is a fixed-size array indexed by hart id.
+const MAX_HARTS: usize = 8;
+
+/// Stash this hart's id in `tp` so later [`hart_id`] calls can recover it.
+///
+/// Must be called once per hart, during early boot (e.g. from `rust_main`
+/// with the hart id the bootloader/SBI passed in `a0`), before any call to
+/// [`current_processor`]/`run_tasks` on that hart.
+///
+/// # Safety
+/// Clobbers `tp`; must only run before anything else on this hart relies on
+/// `tp` (e.g. thread-local storage).
+pub unsafe fn set_hart_id(hart_id: usize) {
+    core::arch::asm!("mv tp, {0}", in(reg) hart_id);
+}
+
+/// Read this hart's id out of `tp`, where [`set_hart_id`] stashed it on
+/// entry.
+pub fn hart_id() -> usize {
+    let hart_id: usize;
+    unsafe {
+        core::arch::asm!("mv {0}, tp", out(reg) hart_id);
+    }
+    hart_id
 }
 
 lazy_static! {
-    pub static ref PROCESSOR: UPSafeCell<Processor> = unsafe { UPSafeCell::new(Processor::new()) };
+    /// One `Processor` per hart, indexed by [`hart_id`]. The ready queue in
+    /// `TASK_MANAGER` stays a single global structure so idle harts can pull
+    /// work from it, but each hart otherwise tracks its own "current task"
+    /// and idle control flow independently.
+    static ref PROCESSORS: [UPSafeCell<Processor>; MAX_HARTS] =
+        [(); MAX_HARTS].map(|_| unsafe { UPSafeCell::new(Processor::new()) });
+}
+
+/// The calling hart's `Processor`
+///
+/// # Panics
+/// Panics if `hart_id()` returns an id `>= MAX_HARTS` — either a hart that
+/// was never accounted for when sizing `PROCESSORS`, or `set_hart_id` was
+/// never called on it and `tp` is still garbage.
+fn current_processor() -> &'static UPSafeCell<Processor> {
+    let id = hart_id();
+    assert!(
+        id < MAX_HARTS,
+        "hart id {} is out of range (MAX_HARTS = {}); did boot call set_hart_id?",
+        id,
+        MAX_HARTS
+    );
+    &PROCESSORS[id]
 }
 
 ///The main part of process execution and scheduling
 ///Loop `fetch_task` to get the process that needs to run, and switch the process through `__switch`
+///
+/// Runs independently on every hart; `fetch_task` pulls from the single
+/// shared `TASK_MANAGER`, which guards its ready queue behind a
+/// [`crate::sync::SpinSafeCell`] (not a `UPSafeCell`) so concurrent fetches
+/// from multiple harts are actually safe rather than merely racy.
+///
+/// Kernel threads ride along the same path: `__switch` only ever touches
+/// `TaskContext`, which every schedulable entity has, so dispatching one
+/// here needs no special case. It's `current_user_token`/`current_trap_cx`
+/// that must refuse to be called for one, since those assume a user task.
 pub fn run_tasks() {
     loop {
-        let mut processor = PROCESSOR.exclusive_access();
+        let mut processor = current_processor().exclusive_access();
         if let Some(task) = fetch_task() {
             let idle_task_cx_ptr = processor.get_idle_task_cx_ptr();
             // access coming task TCB exclusively
             let mut task_inner = task.inner_exclusive_access();
+            // Advance the stride scheduler: every time a task is dispatched
+            // it pays its `pass`, so higher-priority (lower-pass) tasks are
+            // picked more often without ever starving lower-priority ones.
+            task_inner.stride = task_inner.stride.wrapping_add(task_inner.pass);
             let next_task_cx_ptr = &task_inner.task_cx as *const TaskContext;
             task_inner.task_status = TaskStatus::Running;
             // release coming task_inner manually
@@ -146,31 +303,99 @@ pub fn run_tasks() {
 
 /// Get current task through take, leaving a None in its place
 pub fn take_current_task() -> Option<Arc<TaskControlBlock>> {
-    PROCESSOR.exclusive_access().take_current()
+    current_processor().exclusive_access().take_current()
 }
 
 /// Get a copy of the current task
 pub fn current_task() -> Option<Arc<TaskControlBlock>> {
-    PROCESSOR.exclusive_access().current()
+    current_processor().exclusive_access().current()
+}
+
+/// Get the pid of the current task.
+pub fn current_task_pid() -> usize {
+    current_task().unwrap().getpid()
+}
+
+/// Fork the current task, add the child to the ready queue, and return the
+/// child's pid. Used by `sys_fork`; see [`TaskControlBlock::fork`].
+pub fn fork_current_task() -> usize {
+    let child = current_task().unwrap().fork();
+    let child_pid = child.getpid();
+    add_task(child);
+    child_pid
+}
+
+/// Replace the current task's address space by loading `elf_data`. Used by
+/// `sys_exec`; see [`TaskControlBlock::exec`].
+pub fn exec_current_task(elf_data: &[u8]) {
+    current_task().unwrap().exec(elf_data);
+}
+
+/// Scan the current task's children for a zombie matching `pid` (or any
+/// child when `pid == -1`), reap it, and write its exit code to
+/// `exit_code_ptr` in the current task's address space. Used by
+/// `sys_waitpid`.
+///
+/// Reaping drops the last `Arc` to the child, so its `KernelStack` is freed
+/// along with the rest of its `TaskControlBlock`, and its pid is returned to
+/// the allocator via `dealloc_pid` so a later `fork` can reuse it.
+///
+/// Returns the reaped child's pid, `-1` if no child matches `pid` at all,
+/// or `-2` if a matching child exists but hasn't exited yet.
+pub fn waitpid_current_task(pid: isize, exit_code_ptr: *mut i32) -> isize {
+    let task = current_task().unwrap();
+    let mut inner = task.inner_exclusive_access();
+
+    if !inner.children.iter().any(|c| pid == -1 || pid as usize == c.getpid()) {
+        return -1;
+    }
+
+    let zombie_idx = inner.children.iter().position(|c| {
+        (pid == -1 || pid as usize == c.getpid())
+            && c.inner_exclusive_access().task_status == TaskStatus::Zombie
+    });
+    let Some(zombie_idx) = zombie_idx else {
+        return -2;
+    };
+
+    let child = inner.children.remove(zombie_idx);
+    assert_eq!(Arc::strong_count(&child), 1);
+    let found_pid = child.getpid();
+    let exit_code = child.inner_exclusive_access().exit_code;
+    drop(inner);
+    drop(child);
+    dealloc_pid(found_pid);
+
+    let token = current_user_token();
+    *crate::mm::translated_refmut(token, exit_code_ptr) = exit_code;
+    found_pid as isize
 }
 
 /// Get the current user token(addr of page table)
+///
+/// # Panics
+/// Panics if the current task is a kernel thread: kernel threads share the
+/// kernel's own address space and were never assigned a user page table.
 pub fn current_user_token() -> usize {
     let task = current_task().unwrap();
+    assert!(!task.is_kernel_thread(), "kernel threads have no user token");
     task.get_user_token()
 }
 
 ///Get the mutable reference to trap context of current task
+///
+/// # Panics
+/// Panics if the current task is a kernel thread: kernel threads never trap
+/// from user mode, so they have no `TrapContext` to restore.
 pub fn current_trap_cx() -> &'static mut TrapContext {
-    current_task()
-        .unwrap()
-        .inner_exclusive_access()
-        .get_trap_cx()
+    let task = current_task().unwrap();
+    assert!(!task.is_kernel_thread(), "kernel threads have no trap context");
+    task.inner_exclusive_access().get_trap_cx()
 }
 
 ///Return to idle control flow for new scheduling
 pub fn schedule(switched_task_cx_ptr: *mut TaskContext) {
-    let mut processor = PROCESSOR.exclusive_access();
+    let mut processor = current_processor().exclusive_access();
     let idle_task_cx_ptr = processor.get_idle_task_cx_ptr();
     drop(processor);
     unsafe {
@@ -180,21 +405,56 @@ pub fn schedule(switched_task_cx_ptr: *mut TaskContext) {
 
 /// count the number of syscall of the current task
 pub fn count_numbers_of_syscall(syscall_id: usize) {
-    PROCESSOR.exclusive_access().count_numbers_of_syscall(syscall_id);
+    current_processor().exclusive_access().count_numbers_of_syscall(syscall_id);
 }
 
 /// get current `Running` task info
 pub fn get_current_task() -> TaskInfo {
-    PROCESSOR.readonly_access().get_current_task()
+    current_processor().readonly_access().get_current_task()
+}
+
+/// Record syscall entry for the profiling subsystem, see
+/// [`Processor::record_syscall_entry`]
+pub fn record_syscall_entry(syscall_id: usize) -> usize {
+    current_processor().exclusive_access().record_syscall_entry(syscall_id)
+}
+
+/// Record syscall exit for the profiling subsystem, see
+/// [`Processor::record_syscall_exit`]
+pub fn record_syscall_exit(syscall_id: usize, entry_time: usize) {
+    current_processor().exclusive_access().record_syscall_exit(syscall_id, entry_time);
+}
+
+/// Snapshot the current task's syscall accounting, used by `sys_task_stat`
+pub fn task_stat() -> TaskStat {
+    current_processor().exclusive_access().task_stat()
 }
 
 
 ///map memory of current task
 pub fn mmap(_start: usize, _len: usize, _port: usize) -> isize {
-    PROCESSOR.exclusive_access().mmap(_start, _len, _port)
+    current_processor().exclusive_access().mmap(_start, _len, _port)
 }
 
 ///map memory of current task
 pub fn munmap(_start: usize, _len: usize) -> isize {
-    PROCESSOR.exclusive_access().munmap(_start, _len)
+    current_processor().exclusive_access().munmap(_start, _len)
+}
+
+/// Set the priority of the current task, used by `sys_set_priority`
+pub fn set_priority(prio: isize) -> isize {
+    current_processor().exclusive_access().set_priority(prio)
+}
+
+/// Resolve a `StorePageFault`/`LoadPageFault`/`InstructionPageFault` trapped
+/// at `fault_addr` by allocating and mapping the faulting page, if it falls
+/// inside one of the current task's lazily-mapped `mmap` regions.
+///
+/// Returns `true` if the fault was resolved and the instruction can be
+/// retried; `false` if `fault_addr` lies in no lazy region, in which case
+/// the trap handler should treat it as a genuine fault and kill the task.
+pub fn handle_lazy_page_fault(fault_addr: usize) -> bool {
+    let task = current_task().unwrap();
+    task.inner_exclusive_access()
+        .alloc_lazy_page(VirtAddr::from(fault_addr))
 }
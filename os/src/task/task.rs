@@ -0,0 +1,484 @@
+//!Implementation of [`TaskControlBlock`]
+use super::manager::BIG_STRIDE;
+use super::pid::pid_alloc;
+use super::processor::SyscallStat;
+use crate::config::MAX_SYSCALL_NUM;
+use crate::mm::{MapPermission, MemorySet, PhysPageNum, VirtAddr, VirtPageNum};
+use crate::sync::{SpinSafeCell, SpinSafeCellGuard};
+use crate::trap::TrapContext;
+use alloc::boxed::Box;
+use alloc::sync::{Arc, Weak};
+use alloc::vec::Vec;
+
+/// A `mmap`'d region that has been recorded but not yet backed by physical
+/// frames. One page out of it is allocated and mapped, with `perm`, the
+/// first time a fault touches it — see [`TaskControlBlockInner::alloc_lazy_page`].
+#[derive(Clone)]
+struct LazyArea {
+    start: VirtAddr,
+    end: VirtAddr,
+    perm: MapPermission,
+}
+
+impl LazyArea {
+    fn contains(&self, va: VirtAddr) -> bool {
+        self.start <= va && va < self.end
+    }
+}
+
+/// Priority a freshly created task starts out with.
+const DEFAULT_PRIORITY: usize = 16;
+
+/// Lowest priority `sys_set_priority` will accept. A priority of 0 would
+/// divide by zero in `pass = BIG_STRIDE / priority`.
+pub const MIN_PRIORITY: usize = 2;
+
+/// Kernel stack size for a single task (user task or kernel thread).
+const KERNEL_STACK_SIZE: usize = 4096 * 4;
+
+/// A task's private kernel stack: what `__switch` saves/restores `sp` into
+/// while the kernel runs on this task's behalf, whether that's handling a
+/// trap for a user task or driving a kernel thread's own control flow. Just
+/// a heap allocation owned by the `TaskControlBlock`, so it's freed like any
+/// other memory once the last `Arc` to it goes away.
+struct KernelStack {
+    data: Box<[u8]>,
+}
+
+impl KernelStack {
+    fn new() -> Self {
+        Self {
+            data: alloc::vec![0u8; KERNEL_STACK_SIZE].into_boxed_slice(),
+        }
+    }
+
+    fn top(&self) -> usize {
+        self.data.as_ptr() as usize + KERNEL_STACK_SIZE
+    }
+}
+
+/// Task control block structure
+pub struct TaskControlBlock {
+    /// Process id
+    pub pid: usize,
+    /// This task's private kernel stack. Just an owned allocation: dropping
+    /// the `TaskControlBlock` (e.g. once `waitpid` reaps it) frees it like
+    /// any other heap memory, no separate recycling step needed.
+    kernel_stack: KernelStack,
+    /// Mutable inner state, behind its own cell so callers lock only what
+    /// they need instead of the whole TCB.
+    ///
+    /// A plain `UPSafeCell` is not enough here: `TASK_MANAGER::fetch` reads
+    /// a ready task's `stride` out of this cell while that very task may
+    /// simultaneously be inspected from another hart (e.g. a parent's
+    /// `waitpid` reading a ready child's `task_status`), so two harts can
+    /// race on the same cell. `SpinSafeCell` makes that safe the same way
+    /// it already does for `TASK_MANAGER`'s ready queue.
+    inner: SpinSafeCell<TaskControlBlockInner>,
+}
+
+/// The execution status of a task
+#[derive(Copy, Clone, PartialEq, Eq, Debug)]
+pub enum TaskStatus {
+    /// Ready to run
+    Ready,
+    /// Currently running
+    Running,
+    /// Exited, not yet reaped by `waitpid`
+    Zombie,
+}
+
+/// A task's resource usage, as reported to user space by `sys_task_info`
+#[derive(Clone)]
+pub struct TaskInfo {
+    /// The task's current status
+    pub status: TaskStatus,
+    /// Flat per-syscall-id call counts
+    pub syscall_times: [u32; MAX_SYSCALL_NUM],
+    /// Milliseconds elapsed since the task was first scheduled
+    pub time: usize,
+}
+
+/// The mutable part of a [`TaskControlBlock`]
+pub struct TaskControlBlockInner {
+    /// Physical page number of the frame the trap context lives in.
+    /// `None` for kernel threads, which never trap from user mode.
+    pub trap_cx_ppn: Option<PhysPageNum>,
+    /// Application data can only lie in `[0, base_size)`
+    pub base_size: usize,
+    /// Saved task context, restored by `__switch` on resume
+    pub task_cx: TaskContext,
+    /// Current execution status
+    pub task_status: TaskStatus,
+    /// This task's own address space. `None` for kernel threads, which run
+    /// in (and share) the kernel's own address space instead.
+    pub memory_set: Option<MemorySet>,
+    /// Whether this is an in-kernel thread rather than a user task: no
+    /// user token, no trap context, never returns to user mode.
+    pub is_kernel_thread: bool,
+    /// Time (ms) this task was first scheduled
+    pub start_time: usize,
+    /// Flat per-syscall-id call counts, exposed via `TaskInfo`
+    pub syscall_times: [u32; MAX_SYSCALL_NUM],
+    /// Richer per-syscall-id accounting (count, cycles, first/last-call
+    /// timestamps), exposed via `TaskStat`/`sys_task_stat`. Reset across
+    /// `exec`, same as `syscall_times`.
+    pub syscall_stats: [SyscallStat; MAX_SYSCALL_NUM],
+    /// This task's position on the stride scheduler's numberline. Only ever
+    /// advanced while the task is dispatched (see `run_tasks`);
+    /// must not be mutated while the task sits in `TASK_MANAGER`'s heap,
+    /// since the heap does not re-order on mutation.
+    pub stride: usize,
+    /// How far `stride` advances each time this task runs:
+    /// `BIG_STRIDE / priority`
+    pub pass: usize,
+    /// Scheduling priority. Minimum [`MIN_PRIORITY`], default
+    /// [`DEFAULT_PRIORITY`].
+    pub priority: usize,
+    /// `mmap`'d regions recorded by `insert_lazy_framed_area`. Consulted by
+    /// `alloc_lazy_page` on a page fault. A region stays here after its pages
+    /// have been faulted in; only `remove_lazy_area` (`munmap`) and `exec`
+    /// take regions out.
+    lazy_areas: Vec<LazyArea>,
+    /// The task that `fork`ed this one, if any. `Weak` so a parent's death
+    /// doesn't get held up by a child that never gets reaped.
+    pub parent: Option<Weak<TaskControlBlock>>,
+    /// Tasks this one `fork`ed that haven't been reaped by `waitpid` yet
+    pub children: Vec<Arc<TaskControlBlock>>,
+    /// Set when the task exits; read by the parent's `waitpid`
+    pub exit_code: i32,
+}
+
+impl TaskControlBlockInner {
+    /// Get the mutable reference to the trap context
+    ///
+    /// # Panics
+    /// Panics for a kernel thread, which has no trap context.
+    pub fn get_trap_cx(&self) -> &'static mut TrapContext {
+        self.trap_cx_ppn
+            .expect("kernel threads have no trap context")
+            .get_mut()
+    }
+
+    /// Record `[start, end)` as lazily mapped with `perm`, without
+    /// allocating any physical frames yet.
+    pub fn insert_lazy_framed_area(&mut self, start: VirtAddr, end: VirtAddr, perm: MapPermission) -> bool {
+        self.lazy_areas.push(LazyArea { start, end, perm });
+        true
+    }
+
+    /// Drop the lazy region recorded for exactly `[start, end)`, if any, so
+    /// a later fault in that range is treated as a genuine fault instead of
+    /// being silently re-mapped by `alloc_lazy_page`. Used by `munmap` to
+    /// undo an `mmap` that was never (fully) faulted in.
+    pub fn remove_lazy_area(&mut self, start: VirtAddr, end: VirtAddr) -> bool {
+        let before = self.lazy_areas.len();
+        self.lazy_areas.retain(|area| !(area.start == start && area.end == end));
+        self.lazy_areas.len() != before
+    }
+
+    /// Resolve a page fault at `va` by allocating and mapping just the
+    /// faulting page, if `va` falls inside a region previously recorded by
+    /// `insert_lazy_framed_area`.
+    ///
+    /// Returns `true` if the fault was resolved, `false` if `va` is in no
+    /// lazy region (a genuine fault; the caller should kill the task).
+    pub fn alloc_lazy_page(&mut self, va: VirtAddr) -> bool {
+        let Some(area) = self.lazy_areas.iter().find(|area| area.contains(va)) else {
+            return false;
+        };
+        let page_start = VirtAddr::from(va.floor());
+        let page_end = VirtAddr::from(VirtPageNum(va.floor().0 + 1));
+        self.memory_set
+            .as_mut()
+            .expect("kernel threads have no address space to fault in")
+            .insert_framed_area(page_start, page_end, area.perm);
+        true
+    }
+}
+
+impl TaskControlBlock {
+    /// Exclusive access to the mutable inner state
+    pub fn inner_exclusive_access(&self) -> SpinSafeCellGuard<'_, TaskControlBlockInner> {
+        self.inner.exclusive_access()
+    }
+
+    /// Build the initial task control block for an app loaded from ELF data
+    pub fn new(elf_data: &[u8], pid: usize) -> Self {
+        let (memory_set, user_sp, entry_point) = MemorySet::from_elf(elf_data);
+        let trap_cx_ppn = memory_set
+            .translate(VirtAddr::from(crate::config::TRAP_CONTEXT).into())
+            .unwrap()
+            .ppn();
+        let kernel_stack = KernelStack::new();
+        let kstack_top = kernel_stack.top();
+        let task_control_block = Self {
+            pid,
+            kernel_stack,
+            inner: SpinSafeCell::new(TaskControlBlockInner {
+                trap_cx_ppn: Some(trap_cx_ppn),
+                base_size: user_sp,
+                task_cx: TaskContext::goto_trap_return(kstack_top),
+                task_status: TaskStatus::Ready,
+                memory_set: Some(memory_set),
+                is_kernel_thread: false,
+                start_time: 0,
+                syscall_times: [0; MAX_SYSCALL_NUM],
+                syscall_stats: [SyscallStat::default(); MAX_SYSCALL_NUM],
+                stride: 0,
+                pass: BIG_STRIDE / DEFAULT_PRIORITY,
+                priority: DEFAULT_PRIORITY,
+                lazy_areas: Vec::new(),
+                parent: None,
+                children: Vec::new(),
+                exit_code: 0,
+            }),
+        };
+        let trap_cx = task_control_block.inner_exclusive_access().get_trap_cx();
+        *trap_cx = TrapContext::app_init_context(entry_point, user_sp);
+        task_control_block
+    }
+
+    /// The `satp` token for this task's address space
+    ///
+    /// # Panics
+    /// Panics for a kernel thread, which has no user address space.
+    pub fn get_user_token(&self) -> usize {
+        self.inner_exclusive_access()
+            .memory_set
+            .as_ref()
+            .expect("kernel threads have no user token")
+            .token()
+    }
+
+    /// Map a fresh, eagerly frame-backed area into this task's address space
+    pub fn insert_framed_area(&self, start: VirtAddr, end: VirtAddr, perm: MapPermission) -> bool {
+        self.inner_exclusive_access()
+            .memory_set
+            .as_mut()
+            .expect("kernel threads have no address space to map into")
+            .insert_framed_area(start, end, perm);
+        true
+    }
+
+    /// Record `[start, end)` as a lazily mapped area: no frames are
+    /// allocated until a page fault actually touches one, handled by
+    /// [`TaskControlBlockInner::alloc_lazy_page`] via
+    /// `crate::task::handle_lazy_page_fault`.
+    pub fn insert_lazy_framed_area(&self, start: VirtAddr, end: VirtAddr, perm: MapPermission) -> bool {
+        self.inner_exclusive_access()
+            .insert_lazy_framed_area(start, end, perm)
+    }
+
+    /// Unmap a previously mapped area. Handles both kinds `mmap` can
+    /// produce: a `[start, end)` still sitting in `lazy_areas` untouched by
+    /// any fault (never reached `memory_set` at all) and one (partially)
+    /// faulted in, which needs removing from `memory_set` too. Succeeds if
+    /// either was actually present.
+    pub fn free_framed_area(&self, start: VirtAddr, end: VirtAddr) -> bool {
+        let mut inner = self.inner_exclusive_access();
+        let removed_lazy = inner.remove_lazy_area(start, end);
+        let removed_mapped = inner
+            .memory_set
+            .as_mut()
+            .expect("kernel threads have no address space to unmap from")
+            .remove_area_with_start_vpn(start.floor(), end.ceil());
+        removed_lazy || removed_mapped
+    }
+
+    /// This task's pid
+    pub fn getpid(&self) -> usize {
+        self.pid
+    }
+
+    /// Whether this is an in-kernel thread rather than a user task, see
+    /// [`TaskControlBlockInner::is_kernel_thread`]
+    pub fn is_kernel_thread(&self) -> bool {
+        self.inner_exclusive_access().is_kernel_thread
+    }
+
+    /// Duplicate this task into a freshly allocated child: a copy of its
+    /// address space (including any still-lazy `mmap` regions) and trap
+    /// context, under a new pid, linked back to `self` as parent. The
+    /// child's `a0` is set to `0` so the `fork` syscall wrapper can give the
+    /// parent the child's pid while the child sees `0`.
+    pub fn fork(self: &Arc<Self>) -> Arc<Self> {
+        let mut parent_inner = self.inner_exclusive_access();
+        let memory_set = MemorySet::from_existing_user(
+            parent_inner
+                .memory_set
+                .as_ref()
+                .expect("kernel threads cannot fork"),
+        );
+        let trap_cx_ppn = memory_set
+            .translate(VirtAddr::from(crate::config::TRAP_CONTEXT).into())
+            .unwrap()
+            .ppn();
+        let pid = pid_alloc();
+        let kernel_stack = KernelStack::new();
+        let kstack_top = kernel_stack.top();
+        let child = Arc::new(Self {
+            pid,
+            kernel_stack,
+            inner: SpinSafeCell::new(TaskControlBlockInner {
+                trap_cx_ppn: Some(trap_cx_ppn),
+                base_size: parent_inner.base_size,
+                task_cx: TaskContext::goto_trap_return(kstack_top),
+                task_status: TaskStatus::Ready,
+                memory_set: Some(memory_set),
+                is_kernel_thread: false,
+                start_time: 0,
+                syscall_times: [0; MAX_SYSCALL_NUM],
+                syscall_stats: [SyscallStat::default(); MAX_SYSCALL_NUM],
+                // Seeded from the parent's own stride, not 0: the scheduler's
+                // wrapping comparison assumes every live stride stays within
+                // BIG_STRIDE of the others, which a fresh 0 could eventually
+                // violate if the parent has been running a long time.
+                stride: parent_inner.stride,
+                pass: BIG_STRIDE / parent_inner.priority,
+                priority: parent_inner.priority,
+                lazy_areas: parent_inner.lazy_areas.clone(),
+                parent: Some(Arc::downgrade(self)),
+                children: Vec::new(),
+                exit_code: 0,
+            }),
+        });
+        parent_inner.children.push(child.clone());
+        child.inner_exclusive_access().get_trap_cx().x[10] = 0;
+        child
+    }
+
+    /// Replace this task's address space by loading `elf_data`, keeping the
+    /// same pid. Used by the `exec` syscall.
+    pub fn exec(&self, elf_data: &[u8]) {
+        let (memory_set, user_sp, entry_point) = MemorySet::from_elf(elf_data);
+        let trap_cx_ppn = memory_set
+            .translate(VirtAddr::from(crate::config::TRAP_CONTEXT).into())
+            .unwrap()
+            .ppn();
+        let mut inner = self.inner_exclusive_access();
+        inner.memory_set = Some(memory_set);
+        inner.trap_cx_ppn = Some(trap_cx_ppn);
+        inner.base_size = user_sp;
+        inner.lazy_areas.clear();
+        inner.syscall_times = [0; MAX_SYSCALL_NUM];
+        inner.syscall_stats = [SyscallStat::default(); MAX_SYSCALL_NUM];
+        let trap_cx = inner.get_trap_cx();
+        *trap_cx = TrapContext::app_init_context(entry_point, user_sp);
+    }
+}
+
+/// Saved registers that `__switch` swaps on a context switch: the return
+/// address it resumes at, the stack pointer, and the callee-saved `s0..s11`.
+#[repr(C)]
+#[derive(Copy, Clone)]
+pub struct TaskContext {
+    /// Return address, i.e. where `__switch` resumes execution
+    ra: usize,
+    /// Stack pointer
+    sp: usize,
+    /// Callee-saved registers s0..s11
+    s: [usize; 12],
+}
+
+impl TaskContext {
+    /// An all-zero context, used before a `Processor`'s idle control flow
+    /// has ever actually run
+    pub fn zero_init() -> Self {
+        Self {
+            ra: 0,
+            sp: 0,
+            s: [0; 12],
+        }
+    }
+
+    /// A context that resumes at `trap_return`, used for a freshly created
+    /// user task's first dispatch. `kstack_top` is the task's own kernel
+    /// stack, i.e. what `__switch` will set `sp` to on this first resume.
+    pub fn goto_trap_return(kstack_top: usize) -> Self {
+        Self {
+            ra: crate::trap::trap_return as usize,
+            sp: kstack_top,
+            s: [0; 12],
+        }
+    }
+
+    /// A context that resumes at `kernel_thread_trampoline` with `entry`
+    /// stashed in `s0`, used for a freshly created kernel thread's first
+    /// dispatch. See [`kthread_create`].
+    fn goto_kernel_thread_trampoline(entry: fn(), kstack_top: usize) -> Self {
+        let mut cx = Self {
+            ra: kernel_thread_trampoline as usize,
+            sp: kstack_top,
+            s: [0; 12],
+        };
+        cx.s[0] = entry as usize;
+        cx
+    }
+}
+
+/// Spawn a kernel thread running `entry` on its own stack, scheduled
+/// through the same `TASK_MANAGER`/`Processor` path as user tasks.
+///
+/// Kernel threads share the kernel's address space (no user token, no trap
+/// context), so `Processor` must never try to treat one as a user task; see
+/// `current_user_token`/`current_trap_cx`.
+pub fn kthread_create(entry: fn()) {
+    let pid = pid_alloc();
+    let kernel_stack = KernelStack::new();
+    let kstack_top = kernel_stack.top();
+    let task = Arc::new(TaskControlBlock {
+        pid,
+        kernel_stack,
+        inner: SpinSafeCell::new(TaskControlBlockInner {
+            trap_cx_ppn: None,
+            base_size: 0,
+            task_cx: TaskContext::goto_kernel_thread_trampoline(entry, kstack_top),
+            task_status: TaskStatus::Ready,
+            memory_set: None,
+            is_kernel_thread: true,
+            start_time: 0,
+            syscall_times: [0; MAX_SYSCALL_NUM],
+            syscall_stats: [SyscallStat::default(); MAX_SYSCALL_NUM],
+            stride: 0,
+            pass: BIG_STRIDE / DEFAULT_PRIORITY,
+            priority: DEFAULT_PRIORITY,
+            lazy_areas: Vec::new(),
+            parent: None,
+            children: Vec::new(),
+            exit_code: 0,
+        }),
+    });
+    super::add_task(task);
+}
+
+/// First-dispatch trampoline for kernel threads. `__switch` restores
+/// `TaskContext.s0..s11` before jumping to `ra`, so `kthread_create` stashed
+/// the entry function pointer in `s0`; pull it back out, call it, and fall
+/// through to `kthread_exit` once it returns.
+#[naked]
+unsafe extern "C" fn kernel_thread_trampoline() -> ! {
+    core::arch::asm!(
+        "mv a0, s0",
+        "jalr ra, 0(a0)",
+        "call {kthread_exit}",
+        kthread_exit = sym kthread_exit,
+        options(noreturn)
+    )
+}
+
+/// Retire the calling kernel thread once its entry function returns. Kernel
+/// threads have no parent `waitpid`ing on them, so there's no reaping to do
+/// beyond this: mark the task a zombie, drop our reference, and hand control
+/// back to the hart's idle loop for good. Once the last `Arc` to the task
+/// goes (nothing else should be holding one by then), its `KernelStack` is
+/// freed along with it; its pid is never recycled, since nothing ever calls
+/// `waitpid` on a kernel thread.
+extern "C" fn kthread_exit() -> ! {
+    let task = super::take_current_task().unwrap();
+    task.inner_exclusive_access().task_status = TaskStatus::Zombie;
+    drop(task);
+    let mut unused = TaskContext::zero_init();
+    super::schedule(&mut unused as *mut _);
+    unreachable!("a reaped kernel thread should never be rescheduled");
+}